@@ -1,52 +1,142 @@
 use std::collections::hash_map::{HashMap, Entry};
 
-use crate::symbol::{self, Symbol};
+use crate::symbol::Symbol;
 use super::{
-	mem::{SlotIx, SlotKind, FrameInfo},
+	mem::{SlotIx, SlotKind, Upvalue, UpvalueIx, FrameInfo},
 	Error,
+	Warning,
 	SourcePos,
 };
 
 
+/// A variable declared in some [`Scope`], tracking whether it has been read
+/// at least once.
+#[derive(Debug)]
+struct Declaration {
+	slot_ix: SlotIx,
+	pos: SourcePos,
+	/// Whether this declaration stands for compiler-inserted machinery rather
+	/// than a name the user wrote. Synthetic declarations are never reported
+	/// as unused.
+	synthetic: bool,
+	used: bool,
+	/// Whether the slot has been assigned an initial value yet.
+	initialised: bool,
+}
+
+
 /// A lexical scope, which translates identifiers to slot indexes.
 #[derive(Debug, Default)]
 struct Scope {
-	variables: HashMap<Symbol, SlotIx>,
+	variables: HashMap<Symbol, Declaration>,
 }
 
 
 impl Scope {
 	/// Try to declare a variable in the current scope with the given index.
 	/// If the variable is already declared, returns false.
-	fn declare(&mut self, symbol: Symbol, ix: SlotIx) -> bool {
+	fn declare(&mut self, symbol: Symbol, ix: SlotIx, pos: SourcePos, synthetic: bool) -> bool {
 		match self.variables.entry(symbol) {
 			Entry::Occupied(_) => false,
 
 			Entry::Vacant(entry) => {
-				entry.insert(ix);
+				entry.insert(Declaration { slot_ix: ix, pos, synthetic, used: false, initialised: synthetic });
 				true
 			}
 		}
 	}
 
 
-	/// Resolve the index for an already declared variable.
-	fn resolve(&mut self, symbol: Symbol) -> Option<SlotIx> {
+	/// Resolve the index for an already declared variable, marking it as used.
+	/// Also returns whether the variable has been initialised yet.
+	fn resolve(&mut self, symbol: Symbol) -> Option<(SlotIx, bool)> {
 		self.variables
-			.get(&symbol)
-			.copied()
+			.get_mut(&symbol)
+			.map(|declaration| {
+				declaration.used = true;
+				(declaration.slot_ix, declaration.initialised)
+			})
+	}
+
+
+	/// Mark the variable occupying the given slot as initialised, if it
+	/// belongs to this scope.
+	fn mark_initialised(&mut self, slot_ix: SlotIx) -> bool {
+		self.variables
+			.values_mut()
+			.find(|declaration| declaration.slot_ix == slot_ix)
+			.map(|declaration| declaration.initialised = true)
+			.is_some()
+	}
+
+
+	/// The user-declared variables in this scope that were never resolved.
+	fn unused(&self) -> impl Iterator<Item = (Symbol, SourcePos)> + '_ {
+		self.variables
+			.iter()
+			.filter(|(_, declaration)| !declaration.synthetic && !declaration.used)
+			.map(|(&symbol, declaration)| (symbol, declaration.pos))
+	}
+}
+
+
+/// The location a name was resolved to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolvedName {
+	/// The name is a local variable of the frame it was resolved in.
+	Local(SlotIx),
+	/// The name is captured from an enclosing frame.
+	Upvalue(UpvalueIx),
+	/// The name is a module-level global, addressed directly rather than
+	/// through the enclosing frames.
+	Global(SlotIx),
+}
+
+
+/// A cached name resolution, memoized per [`Frame`] so that a name referenced
+/// more than once doesn't have to walk the frame/upvalue/global chain again.
+/// Unlike [`ResolvedName`], this also remembers a previously failed lookup, so
+/// that repeatedly referencing a typo doesn't repeat the walk either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameLocation {
+	Local(SlotIx),
+	Upvalue(UpvalueIx),
+	Global(SlotIx),
+	Undeclared,
+}
+
+
+impl From<ResolvedName> for NameLocation {
+	fn from(resolved: ResolvedName) -> Self {
+		match resolved {
+			ResolvedName::Local(slot_ix) => Self::Local(slot_ix),
+			ResolvedName::Upvalue(upvalue_ix) => Self::Upvalue(upvalue_ix),
+			ResolvedName::Global(slot_ix) => Self::Global(slot_ix),
+		}
 	}
 }
 
 
 /// A function lexical scope.
-/// Includes information about local variables.
+/// Includes information about local variables and captured upvalues.
 #[derive(Debug)]
 struct Frame {
 	/// A memory slot for each variable.
 	slots: Vec<SlotKind>,
 	/// Stack of scopes in the frame.
 	scopes: Vec<Scope>,
+	/// The upvalues captured by this frame, in the order they were first
+	/// referenced.
+	upvalues: Vec<Upvalue>,
+	/// Index of the upvalue already installed for a given name, so that a
+	/// name captured more than once in the same frame is threaded at most
+	/// once.
+	upvalue_names: HashMap<Symbol, UpvalueIx>,
+	/// Memoized outcome of resolving a name from this frame, so that the
+	/// second and later references to the same name don't re-walk the scopes,
+	/// upvalues or globals. Entries are invalidated whenever a new
+	/// declaration could shadow them, see `declare` and `exit_block`.
+	resolved: HashMap<Symbol, NameLocation>,
 }
 
 
@@ -56,6 +146,9 @@ impl Frame {
 		Self {
 			slots: Vec::new(),
 			scopes: Vec::new(),
+			upvalues: Vec::new(),
+			upvalue_names: HashMap::new(),
+			resolved: HashMap::new(),
 		}
 	}
 
@@ -66,12 +159,21 @@ impl Frame {
 	}
 
 
-	/// Exit the current scope.
+	/// Exit the current scope, returning the declarations that were never used.
 	/// Panics if the stack is empty.
-	fn exit_block(&mut self) {
-		self.scopes
+	fn exit_block(&mut self) -> Vec<(Symbol, SourcePos)> {
+		let scope = self.scopes
 			.pop()
 			.expect("attempt to exit empty stack");
+
+		// Names declared in the exiting scope no longer resolve to it; drop
+		// any cached resolution so the name is looked up again, possibly now
+		// finding an outer binding instead.
+		for &symbol in scope.variables.keys() {
+			self.invalidate(symbol);
+		}
+
+		scope.unused().collect()
 	}
 
 
@@ -81,8 +183,13 @@ impl Frame {
 		let scope = self.scopes.last_mut().expect("attempt to declare in empty stack");
 		let slot_ix = SlotIx(self.slots.len() as u32);
 
-		if scope.declare(symbol, slot_ix) {
+		if scope.declare(symbol, slot_ix, pos, false) {
 			self.slots.push(SlotKind::Regular);
+
+			// The new declaration may shadow a binding from an outer scope of
+			// this same frame that got memoized before this point.
+			self.invalidate(symbol);
+
 			Ok(slot_ix)
 		} else {
 			Err(Error::duplicate_variable(symbol, pos))
@@ -90,28 +197,130 @@ impl Frame {
 	}
 
 
-	/// Resolve a symbol in the current frame.
-	fn resolve(&mut self, symbol: Symbol) -> Option<SlotIx> {
+	/// Enter a new block and pre-declare a batch of names in it before any of
+	/// their initializers are compiled, so that sibling definitions in a
+	/// recursive block can resolve each other. Rejects duplicate names within
+	/// the batch the same way a regular declaration would.
+	/// On error, the block is rolled back as if it had never been entered —
+	/// including reclaiming the slot indices already allocated to names
+	/// declared earlier in the same batch — so a caller that recovers from
+	/// the error to keep collecting diagnostics doesn't leak a dead slot per
+	/// occurrence.
+	fn enter_recursive_block(&mut self, symbols: &[Symbol], pos: SourcePos) -> Result<Vec<SlotIx>, Error> {
+		self.enter_block();
+
+		let slots_before = self.slots.len();
+
+		let slots = symbols
+			.iter()
+			.map(|&symbol| self.declare(symbol, pos))
+			.collect::<Result<Vec<_>, _>>();
+
+		if slots.is_err() {
+			self.exit_block();
+			self.slots.truncate(slots_before);
+		}
+
+		slots
+	}
+
+
+	/// Resolve a symbol to a local variable or an already-installed upvalue of
+	/// this frame. The returned flag reports whether a resolved local has
+	/// already been initialised; it is always `true` for upvalues.
+	fn resolve(&mut self, symbol: Symbol) -> Option<(ResolvedName, bool)> {
+		if let Some((slot_ix, initialised)) = self.scopes.iter_mut().rev().find_map(|scope| scope.resolve(symbol)) {
+			return Some((ResolvedName::Local(slot_ix), initialised))
+		}
+
+		self.upvalue_names
+			.get(&symbol)
+			.copied()
+			.map(|upvalue_ix| (ResolvedName::Upvalue(upvalue_ix), true))
+	}
+
+
+	/// Mark the variable occupying the given slot as initialised.
+	fn mark_initialised(&mut self, slot_ix: SlotIx) {
 		self.scopes
 			.iter_mut()
 			.rev()
+			.any(|scope| scope.mark_initialised(slot_ix));
+	}
+
+
+	/// Whether the local variable in the given slot has been initialised.
+	/// Slots that aren't found (not a plain local declaration) are treated as
+	/// initialised, since the check only matters for locals.
+	fn is_initialised(&self, slot_ix: SlotIx) -> bool {
+		self.scopes
+			.iter()
+			.rev()
 			.find_map(
-				|scope| scope.resolve(symbol)
+				|scope| scope.variables
+					.values()
+					.find(|declaration| declaration.slot_ix == slot_ix)
+					.map(|declaration| declaration.initialised)
 			)
+			.unwrap_or(true)
+	}
+
+
+	/// Look up a memoized name resolution for this frame.
+	fn cached(&self, symbol: Symbol) -> Option<NameLocation> {
+		self.resolved.get(&symbol).copied()
+	}
+
+
+	/// Memoize a name resolution for this frame.
+	fn cache(&mut self, symbol: Symbol, location: NameLocation) {
+		self.resolved.insert(symbol, location);
+	}
+
+
+	/// Drop a memoized resolution for this frame, if any.
+	fn invalidate(&mut self, symbol: Symbol) {
+		self.resolved.remove(&symbol);
 	}
 
 
-	/// Resolve or declare a symbol in the current scope.
+	/// Install (or reuse) an upvalue capturing the given parent location under
+	/// this name.
+	fn capture(&mut self, symbol: Symbol, upvalue: Upvalue) -> UpvalueIx {
+		if let Some(&upvalue_ix) = self.upvalue_names.get(&symbol) {
+			return upvalue_ix
+		}
+
+		// Reuse an existing upvalue that already captures the same parent
+		// location, even if it was installed under a different name.
+		let upvalue_ix = self.upvalues
+			.iter()
+			.position(|installed| *installed == upvalue)
+			.map(|ix| UpvalueIx(ix as u32))
+			.unwrap_or_else(|| {
+				let ix = UpvalueIx(self.upvalues.len() as u32);
+				self.upvalues.push(upvalue);
+				ix
+			});
+
+		self.upvalue_names.insert(symbol, upvalue_ix);
+
+		upvalue_ix
+	}
+
+
+	/// Resolve or declare a synthetic symbol in the root scope.
 	fn resolve_or_declare(&mut self, symbol: Symbol, slot_kind: SlotKind) -> SlotIx {
 		let scope = self.scopes.first_mut().expect("frame missing root scope");
 
 		match scope.variables.entry(symbol) {
-			Entry::Occupied(entry) => *entry.get(),
+			Entry::Occupied(entry) => entry.get().slot_ix,
 
 			Entry::Vacant(entry) => {
 				let slot_ix = SlotIx(self.slots.len() as u32);
-				entry.insert(slot_ix);
+				entry.insert(Declaration { slot_ix, pos: SourcePos::default(), synthetic: true, used: true, initialised: true });
 				self.slots.push(slot_kind);
+				self.invalidate(symbol);
 
 				slot_ix
 			}
@@ -130,6 +339,7 @@ impl Into<FrameInfo> for Frame {
 	fn into(self) -> FrameInfo {
 		FrameInfo {
 			slots: self.slots.into(),
+			upvalues: self.upvalues.into(),
 		}
 	}
 }
@@ -139,6 +349,12 @@ impl Into<FrameInfo> for Frame {
 #[derive(Debug, Default)]
 pub struct Stack {
 	frames: Vec<Frame>,
+	/// Module-level globals, resolvable from inside any frame without being
+	/// captured like a regular upvalue.
+	globals: HashMap<Symbol, SlotIx>,
+	/// Non-fatal diagnostics collected while resolving names, such as unused
+	/// variable warnings.
+	diagnostics: Vec<Warning>,
 }
 
 
@@ -160,7 +376,8 @@ impl Stack {
 			.pop()
 			.expect("attempt to exit empty stack");
 
-		frame.exit_block();
+		let unused = frame.exit_block();
+		self.report_unused(unused);
 
 		debug_assert!(frame.scopes.is_empty());
 
@@ -176,7 +393,8 @@ impl Stack {
 
 	/// Exit a new block in the current frame.
 	pub fn exit_block(&mut self) {
-		self.top().exit_block()
+		let unused = self.top().exit_block();
+		self.report_unused(unused);
 	}
 
 
@@ -187,64 +405,119 @@ impl Stack {
 	}
 
 
-	/// Resolve a symbol in the current scope.
-	/// If the symbol is being closed from a parent frame, the capturing of such symbol is
-	/// installed.
-	pub fn resolve(
-		&mut self,
-		symbol: Symbol,
-		pos: SourcePos,
-		interner: &mut symbol::Interner,
-	) -> Result<SlotIx, Error> {
-		// Resolve the frame and slot where the symbol originates from.
-		let (frame_ix, mut slot_ix) = self.frames
-			.iter_mut()
-			.enumerate()
-			.rev()
-			.find_map(
-				|(frame_ix, frame)| {
-					let slot_ix = frame.resolve(symbol)?;
-					Some((frame_ix, slot_ix))
-				}
-			)
-			.ok_or_else(
-				|| Error::undeclared_variable(symbol, pos)
-			)?;
-
-		if frame_ix == self.frames.len() - 1 { // Symbol found in the local frame.
-			return Ok(slot_ix)
-		} else { // Symbol captured from parent frame, must setup capturing.
-			let symbol_captured = {
-				// Create a new unique symbol for the intermediate local variables. Note that this
-				// identifier contains an invalid character, and therefore can not clash with
-				// user-defined identifiers.
-				let mut identifier = interner
-					.resolve(symbol)
-					.expect("unresolved symbol")
-					.to_owned();
-
-				identifier.push_str("@closed");
-
-				interner.get_or_intern(identifier)
-			};
-
-			// Close over the slot in the originating frame.
-			let base_frame = &mut self.frames[frame_ix];
-			base_frame.close(slot_ix);
-
-			// Insert the captured slot in the intermediate frames between the origin and the
-			// destination.
-			let range = frame_ix + 1 .. self.frames.len();
-
-			for frame in &mut self.frames[range] {
-				slot_ix = frame.resolve_or_declare(
-					symbol_captured,
-					SlotKind::Capture { from: slot_ix }
-				);
+	/// Enter a new block and pre-declare a batch of mutually recursive names
+	/// in it, reserving a slot for each before any of their initializers are
+	/// compiled.
+	/// Panics if the stack is empty.
+	pub fn enter_recursive_block(&mut self, symbols: &[Symbol], pos: SourcePos) -> Result<Vec<SlotIx>, Error> {
+		self.top().enter_recursive_block(symbols, pos)
+	}
+
+
+	/// Resolve a symbol in the current frame.
+	/// If the symbol originates from an enclosing frame, the upvalue chain
+	/// capturing it is installed in every frame between the origin and the
+	/// current one. If the symbol resolves to a slot in the *current* frame
+	/// that has been declared but not yet initialised, returns
+	/// `Error::use_before_init` instead. Failing all frames, falls back to the
+	/// module's globals before giving up with `Error::undeclared_variable`.
+	///
+	/// The location a name resolves to (but not its initialisation state,
+	/// which is re-checked on every call) is memoized per frame, so repeated
+	/// references to the same name don't re-walk the frame/upvalue/global
+	/// chain.
+	pub fn resolve(&mut self, symbol: Symbol, pos: SourcePos) -> Result<ResolvedName, Error> {
+		let frame_ix = self.frames.len() - 1;
+
+		let location = match self.frames[frame_ix].cached(symbol) {
+			Some(location) => location,
+
+			None => {
+				let location = self.locate(frame_ix, symbol)
+					.map(NameLocation::from)
+					.unwrap_or(NameLocation::Undeclared);
+
+				self.frames[frame_ix].cache(symbol, location);
+
+				location
+			}
+		};
+
+		match location {
+			NameLocation::Local(slot_ix) if self.frames[frame_ix].is_initialised(slot_ix) =>
+				Ok(ResolvedName::Local(slot_ix)),
+
+			NameLocation::Local(_) => Err(Error::use_before_init(symbol, pos)),
+			NameLocation::Upvalue(upvalue_ix) => Ok(ResolvedName::Upvalue(upvalue_ix)),
+			NameLocation::Global(slot_ix) => Ok(ResolvedName::Global(slot_ix)),
+			NameLocation::Undeclared => Err(Error::undeclared_variable(symbol, pos)),
+		}
+	}
+
+
+	/// Resolve a symbol to its location from the given frame, installing
+	/// upvalue capture along the way. Returns `None` if the symbol is neither
+	/// in scope in any frame nor a declared global.
+	fn locate(&mut self, frame_ix: usize, symbol: Symbol) -> Option<ResolvedName> {
+		if let Some((resolved, _)) = self.frames[frame_ix].resolve(symbol) {
+			return Some(resolved)
+		}
+
+		if frame_ix > 0 {
+			if let Some(parent) = self.resolve_in(frame_ix - 1, symbol) {
+				return Some(self.install_upvalue(frame_ix, symbol, parent))
 			}
 		}
 
-		Ok(slot_ix)
+		self.resolve_global(symbol).map(ResolvedName::Global)
+	}
+
+
+	/// Resolve a name starting at the given frame, recursively capturing it as
+	/// an upvalue from every enclosing frame that doesn't have it locally.
+	/// Used only to resolve names in *enclosing* frames, where the
+	/// initialisation check does not apply: by the time a closure runs, the
+	/// captured variable's initializer has long since completed.
+	fn resolve_in(&mut self, frame_ix: usize, symbol: Symbol) -> Option<ResolvedName> {
+		if let Some((resolved, _)) = self.frames[frame_ix].resolve(symbol) {
+			return Some(resolved)
+		}
+
+		if frame_ix == 0 {
+			return None
+		}
+
+		let parent = self.resolve_in(frame_ix - 1, symbol)?;
+
+		Some(self.install_upvalue(frame_ix, symbol, parent))
+	}
+
+
+	/// Install an upvalue in `frame_ix` capturing `parent`, which was resolved
+	/// in the immediately enclosing frame.
+	fn install_upvalue(&mut self, frame_ix: usize, symbol: Symbol, parent: ResolvedName) -> ResolvedName {
+		let upvalue = match parent {
+			ResolvedName::Local(slot_ix) => {
+				self.frames[frame_ix - 1].close(slot_ix);
+				Upvalue::FromLocal(slot_ix)
+			}
+
+			ResolvedName::Upvalue(upvalue_ix) => Upvalue::FromUpvalue(upvalue_ix),
+
+			// Globals are resolved directly by `locate`, which never routes
+			// through `resolve_in`/`install_upvalue`; they are addressed
+			// straight from `Stack::globals` rather than threaded through the
+			// enclosing frames.
+			ResolvedName::Global(_) => unreachable!("globals are not captured as upvalues"),
+		};
+
+		ResolvedName::Upvalue(self.frames[frame_ix].capture(symbol, upvalue))
+	}
+
+
+	/// Mark a previously declared slot in the current frame as initialised.
+	pub fn mark_initialised(&mut self, slot_ix: SlotIx) {
+		self.top().mark_initialised(slot_ix)
 	}
 
 
@@ -254,8 +527,242 @@ impl Stack {
 	}
 
 
+	/// Declare a module-level global, visible from every frame without being
+	/// captured.
+	pub fn declare_global(&mut self, symbol: Symbol, pos: SourcePos) -> Result<SlotIx, Error> {
+		let slot_ix = SlotIx(self.globals.len() as u32);
+
+		match self.globals.entry(symbol) {
+			Entry::Occupied(_) => Err(Error::duplicate_variable(symbol, pos)),
+
+			Entry::Vacant(entry) => {
+				entry.insert(slot_ix);
+
+				// Any frame that already resolved this name — whether to
+				// `Undeclared`, or to a local/upvalue that shadows the
+				// not-yet-declared global — must forget that cached result so
+				// it's looked up again. A shadowing local still wins after
+				// the invalidation, since the fresh lookup goes through
+				// `Frame::resolve`, which checks locals and upvalues before
+				// falling back to the globals.
+				for frame in &mut self.frames {
+					frame.invalidate(symbol);
+				}
+
+				Ok(slot_ix)
+			}
+		}
+	}
+
+
+	/// Resolve a module-level global, if one was declared under this name.
+	pub fn resolve_global(&self, symbol: Symbol) -> Option<SlotIx> {
+		self.globals.get(&symbol).copied()
+	}
+
+
+	/// Take the diagnostics collected so far, such as unused variable warnings.
+	pub fn diagnostics(&mut self) -> Vec<Warning> {
+		std::mem::take(&mut self.diagnostics)
+	}
+
+
 	/// Get the top frame in the stack.
 	fn top(&mut self) -> &mut Frame {
 		self.frames.last_mut().expect("empty stack")
 	}
-}
\ No newline at end of file
+
+
+	/// Turn the unused declarations of an exited scope into warnings.
+	fn report_unused(&mut self, unused: Vec<(Symbol, SourcePos)>) {
+		self.diagnostics.extend(
+			unused
+				.into_iter()
+				.map(|(symbol, pos)| Warning::unused_variable(symbol, pos))
+		);
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::symbol;
+
+	/// `let a = b; let b = 1;` in a recursive block: `a`'s initializer refers
+	/// to `b` before `b` has been initialised, which must be rejected rather
+	/// than silently reading garbage.
+	#[test]
+	fn recursive_block_rejects_use_before_init() {
+		let mut interner = symbol::Interner::default();
+		let a = interner.get_or_intern("a");
+		let b = interner.get_or_intern("b");
+		let pos = SourcePos::default();
+
+		let mut stack = Stack::default();
+		stack.enter_frame();
+
+		let slots = stack.enter_recursive_block(&[a, b], pos).unwrap();
+		let (a_slot, b_slot) = (slots[0], slots[1]);
+
+		// Compiling `a`'s initializer, which reads `b`.
+		assert!(matches!(stack.resolve(b, pos), Err(_)));
+
+		stack.mark_initialised(a_slot);
+
+		// Compiling `b`'s initializer, which reads `a` (already initialised).
+		assert_eq!(stack.resolve(a, pos), Ok(ResolvedName::Local(a_slot)));
+
+		stack.mark_initialised(b_slot);
+
+		// Now that both are initialised, `b` resolves cleanly too.
+		assert_eq!(stack.resolve(b, pos), Ok(ResolvedName::Local(b_slot)));
+	}
+
+
+	/// A name resolved (and cached) in an outer block must not keep resolving
+	/// to that cached location once an inner block shadows it, nor once that
+	/// inner block exits again.
+	#[test]
+	fn shadowing_invalidates_the_resolution_cache() {
+		let mut interner = symbol::Interner::default();
+		let x = interner.get_or_intern("x");
+		let pos = SourcePos::default();
+
+		let mut stack = Stack::default();
+		stack.enter_frame();
+
+		let outer_slot = stack.declare(x, pos).unwrap();
+		stack.mark_initialised(outer_slot);
+
+		// First reference: populates the per-frame resolution cache.
+		assert_eq!(stack.resolve(x, pos), Ok(ResolvedName::Local(outer_slot)));
+
+		stack.enter_block();
+
+		let inner_slot = stack.declare(x, pos).unwrap();
+		stack.mark_initialised(inner_slot);
+		assert_ne!(inner_slot, outer_slot);
+
+		// A cache hit here would incorrectly return the outer slot.
+		assert_eq!(stack.resolve(x, pos), Ok(ResolvedName::Local(inner_slot)));
+
+		stack.exit_block();
+
+		// A cache hit here would incorrectly return the now out-of-scope inner slot.
+		assert_eq!(stack.resolve(x, pos), Ok(ResolvedName::Local(outer_slot)));
+	}
+
+
+	/// A grandchild frame capturing a grandparent local must thread an upvalue
+	/// through every frame in between: the parent gets a `FromLocal` upvalue
+	/// closing over the grandparent's slot, and the grandchild gets a
+	/// `FromUpvalue` upvalue referencing the parent's.
+	#[test]
+	fn capture_chains_through_intermediate_frames() {
+		let mut interner = symbol::Interner::default();
+		let x = interner.get_or_intern("x");
+		let pos = SourcePos::default();
+
+		let mut stack = Stack::default();
+
+		stack.enter_frame(); // grandparent
+		let x_slot = stack.declare(x, pos).unwrap();
+		stack.mark_initialised(x_slot);
+
+		stack.enter_frame(); // parent
+		stack.enter_frame(); // grandchild
+
+		let resolved = stack.resolve(x, pos).unwrap();
+
+		let grandchild_upvalue_ix = match resolved {
+			ResolvedName::Upvalue(upvalue_ix) => upvalue_ix,
+			other => panic!("expected an upvalue, got {:?}", other),
+		};
+
+		assert_eq!(stack.frames[0].slots[x_slot.0 as usize], SlotKind::Closed);
+		assert_eq!(stack.frames[1].upvalues, vec![Upvalue::FromLocal(x_slot)]);
+
+		let parent_upvalue_ix = stack.frames[1].upvalue_names[&x];
+		assert_eq!(stack.frames[2].upvalues, vec![Upvalue::FromUpvalue(parent_upvalue_ix)]);
+		assert_eq!(stack.frames[2].upvalue_names[&x], grandchild_upvalue_ix);
+	}
+
+
+	/// Referencing the same captured name twice in one frame must thread only
+	/// one `Upvalue` entry, reusing the same index both times.
+	#[test]
+	fn capture_is_deduplicated_within_a_frame() {
+		let mut interner = symbol::Interner::default();
+		let x = interner.get_or_intern("x");
+		let pos = SourcePos::default();
+
+		let mut stack = Stack::default();
+
+		stack.enter_frame(); // parent
+		let x_slot = stack.declare(x, pos).unwrap();
+		stack.mark_initialised(x_slot);
+
+		stack.enter_frame(); // child
+
+		let first = stack.resolve(x, pos).unwrap();
+		let second = stack.resolve(x, pos).unwrap();
+
+		assert_eq!(first, second);
+		assert_eq!(stack.frames[1].upvalues.len(), 1);
+	}
+
+
+	/// Resolving a global from inside a nested frame must not install any
+	/// capture machinery: the result is `ResolvedName::Global`, and every
+	/// intervening frame's upvalues stay untouched.
+	#[test]
+	fn global_resolution_does_not_trigger_capture() {
+		let mut interner = symbol::Interner::default();
+		let g = interner.get_or_intern("g");
+		let pos = SourcePos::default();
+
+		let mut stack = Stack::default();
+
+		let slot_ix = stack.declare_global(g, pos).unwrap();
+
+		stack.enter_frame();
+		stack.enter_frame(); // intervening frame
+
+		assert_eq!(stack.resolve(g, pos), Ok(ResolvedName::Global(slot_ix)));
+
+		for frame in &stack.frames {
+			assert!(frame.upvalues.is_empty());
+			assert!(frame.upvalue_names.is_empty());
+		}
+	}
+
+
+	/// `Stack::diagnostics()` must report an unread local as
+	/// `Warning::unused_variable`, must not report one that was resolved, and
+	/// must never report a synthetic slot installed via `resolve_or_declare`,
+	/// even though it's never read either.
+	#[test]
+	fn diagnostics_reports_only_unread_user_declarations() {
+		let mut interner = symbol::Interner::default();
+		let unused = interner.get_or_intern("unused");
+		let used = interner.get_or_intern("used");
+		let synthetic = interner.get_or_intern("synthetic");
+		let pos = SourcePos::default();
+
+		let mut stack = Stack::default();
+		stack.enter_frame();
+
+		stack.declare(unused, pos).unwrap();
+
+		let used_slot = stack.declare(used, pos).unwrap();
+		stack.mark_initialised(used_slot);
+		stack.resolve(used, pos).unwrap();
+
+		stack.resolve_or_declare(synthetic);
+
+		stack.exit_frame();
+
+		assert_eq!(stack.diagnostics(), vec![Warning::unused_variable(unused, pos)]);
+	}
+}